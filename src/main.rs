@@ -1,6 +1,9 @@
 extern crate tcod;
 extern crate rand;
 
+use std::cmp;
+
+use rand::Rng;
 use rand::distributions::{Normal, IndependentSample};
 use tcod::console::*;
 use tcod::colors::{self, Color, lerp};
@@ -14,6 +17,10 @@ mod mapgen;
 const SCREEN_WIDTH: i32 = 80;
 const SCREEN_HEIGHT: i32 = 50;
 
+// size of the scrolling viewport onto the map, which is usually much larger than this
+const DISPLAY_WIDTH: i32 = 80;
+const DISPLAY_HEIGHT: i32 = 50;
+
 const LIMIT_FPS: i32 = 20;
 
 const FOV_ALGO: FovAlgorithm = FovAlgorithm::Shadow;
@@ -44,6 +51,77 @@ const COLOR_FOG_GROUND: Color = Color {
 };
 const COLOR_FOG_WALL: Color = Color { r: 0, g: 0, b: 50 };
 
+const MONSTER_SIGHT_RADIUS: i32 = 8;
+
+/// where the game is in the turn cycle: waiting on the player, or running a turn's worth of
+/// world simulation in response to the action they just took
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunState {
+    WaitingForInput,
+    PlayerTurn,
+    MonsterTurn,
+}
+
+/// a `DISPLAY_WIDTH` x `DISPLAY_HEIGHT` window onto the map, centered on the player so maps
+/// bigger than the screen can still be explored
+struct Camera {
+    left_x: i32,
+    top_y: i32,
+}
+
+impl Camera {
+    pub fn new(center_x: i32, center_y: i32) -> Self {
+        let mut camera = Camera { left_x: 0, top_y: 0 };
+        camera.center_on(center_x, center_y);
+        camera
+    }
+
+    /// recenter the viewport on a world coordinate, clamping so it never shows
+    /// out-of-bounds tiles
+    pub fn center_on(&mut self, x: i32, y: i32) {
+        self.left_x = cmp::max(0, cmp::min(x - DISPLAY_WIDTH / 2, MAP_WIDTH - DISPLAY_WIDTH));
+        self.top_y = cmp::max(0, cmp::min(y - DISPLAY_HEIGHT / 2, MAP_HEIGHT - DISPLAY_HEIGHT));
+    }
+
+    pub fn right_x(&self) -> i32 {
+        self.left_x + DISPLAY_WIDTH
+    }
+
+    pub fn bottom_y(&self) -> i32 {
+        self.top_y + DISPLAY_HEIGHT
+    }
+
+    /// translate a world coordinate into screen space, or `None` if it's outside the
+    /// current viewport
+    pub fn to_screen(&self, x: i32, y: i32) -> Option<(i32, i32)> {
+        if x >= self.left_x && x < self.right_x() && y >= self.top_y && y < self.bottom_y() {
+            Some((x - self.left_x, y - self.top_y))
+        } else {
+            None
+        }
+    }
+}
+
+/// a cached set of tiles an object can currently light up or see, recomputed only when
+/// `dirty` is set (on creation, when the range changes, or when the object has moved since
+/// the cache was last built)
+#[derive(Debug, Clone)]
+struct Viewshed {
+    visible_tiles: Vec<(i32, i32)>,
+    range: i32,
+    dirty: bool,
+}
+
+impl Viewshed {
+    pub fn new(range: i32) -> Self {
+        Viewshed {
+            visible_tiles: Vec::new(),
+            range: range,
+            dirty: true,
+        }
+    }
+}
+
 /// This is a generic object: the player, a monster, an item, the stairs...
 /// It's always represented by a character on screen.
 #[derive(Debug)]
@@ -52,83 +130,168 @@ struct Object {
     y: i32,
     char: char,
     color: Color,
-    pub torch_distance: i32,
+    viewshed: Viewshed,
+    /// objects that block prevent the player and monsters from walking onto their tile
+    blocks: bool,
+    /// true for objects that take a turn chasing the player when it's in sight
+    monster: bool,
 }
 
 impl Object {
-    pub fn new(x: i32, y: i32, char: char, color: Color) -> Self {
+    pub fn new(x: i32, y: i32, char: char, color: Color, blocks: bool) -> Self {
         Object {
             x: x,
             y: y,
             char: char,
             color: color,
-            torch_distance: 0,
+            viewshed: Viewshed::new(0),
+            blocks: blocks,
+            monster: false,
         }
     }
 
-    /// move by the given amount, if the destination is not blocked
-    pub fn move_by(&mut self, dx: i32, dy: i32, map: &Map) {
-        if !map[(self.x + dx) as usize][(self.y + dy) as usize].blocked {
-            self.x += dx;
-            self.y += dy;
+    /// give this object a light radius, marking its viewshed dirty so it gets computed on
+    /// the next render
+    pub fn set_light_range(&mut self, range: i32) {
+        self.viewshed.range = range;
+        self.viewshed.dirty = true;
+    }
+
+    /// set the color and then draw the character that represents this object at its
+    /// position, if the camera currently has it in view
+    pub fn draw(&self, con: &mut Console, camera: &Camera) {
+        if let Some((screen_x, screen_y)) = camera.to_screen(self.x, self.y) {
+            con.set_default_foreground(self.color);
+            con.put_char(screen_x, screen_y, self.char, BackgroundFlag::None);
+        }
+    }
+
+    /// Erase the character that represents this object, if the camera currently has it in view
+    pub fn clear(&self, con: &mut Console, camera: &Camera) {
+        if let Some((screen_x, screen_y)) = camera.to_screen(self.x, self.y) {
+            con.put_char(screen_x, screen_y, ' ', BackgroundFlag::None);
         }
     }
+}
 
-    /// set the color and then draw the character that represents this object at its position
-    pub fn draw(&self, con: &mut Console) {
-        con.set_default_foreground(self.color);
-        con.put_char(self.x, self.y, self.char, BackgroundFlag::None);
+/// returns true if a tile is off the map, is a wall, or is occupied by a blocking object
+fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
+    match map.get(x, y) {
+        Some(tile) if !tile.blocked() => {}
+        _ => return true,
     }
+    objects.iter().any(|object| object.blocks && object.x == x && object.y == y)
+}
 
-    /// Erase the character that represents this object
-    pub fn clear(&self, con: &mut Console) {
-        con.put_char(self.x, self.y, ' ', BackgroundFlag::None);
+/// move the object with the given index by the given amount, if the destination is not blocked
+fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) {
+    let (x, y) = (objects[id].x, objects[id].y);
+    if !is_blocked(x + dx, y + dy, map, objects) {
+        objects[id].x += dx;
+        objects[id].y += dy;
+        objects[id].viewshed.dirty = true;
+    }
+}
+
+/// have a monster take its turn: step toward the player if it can see them, otherwise stay put
+fn ai_take_turn(monster_id: usize, map: &Map, objects: &mut [Object]) {
+    let (monster_x, monster_y) = (objects[monster_id].x, objects[monster_id].y);
+    let (player_x, player_y) = (objects[0].x, objects[0].y);
+
+    let mut monster_fov = FovMap::new(map.width, map.height);
+    for y in 0..map.height {
+        for x in 0..map.width {
+            let tile = map.get(x, y).unwrap();
+            monster_fov.set(x, y, !tile.block_sight(), !tile.blocked());
+        }
+    }
+    monster_fov.compute_fov(monster_x,
+                             monster_y,
+                             MONSTER_SIGHT_RADIUS,
+                             FOV_LIGHT_WALLS,
+                             FOV_ALGO);
+
+    if monster_fov.is_in_fov(player_x, player_y) {
+        let dx = player_x - monster_x;
+        let dy = player_y - monster_y;
+        let (step_x, step_y) = if dx.abs() > dy.abs() {
+            (dx.signum(), 0)
+        } else {
+            (0, dy.signum())
+        };
+        move_by(monster_id, step_x, step_y, map, objects);
     }
 }
 
 fn render_all(root: &mut Root,
               con: &mut Offscreen,
-              objects: &[Object],
+              objects: &mut [Object],
               map: &mut Map,
-              fov_map: &mut FovMap) {
-    // Compute lighting
+              fov_map: &mut FovMap,
+              camera: &Camera) {
+    // snapshot the player's dirty flag before anything below has a chance to clear it
+    let player_dirty = objects[0].viewshed.dirty;
+
+    // Compute lighting, rebuilding an object's cached viewshed only when it has moved
+    // since the last time it was computed
     map.clear_light();
-    for object in objects {
-        if object.torch_distance > 0 {
-            let torch_intensity_shift = Normal::new(0.0, 0.05)
-                .ind_sample(&mut rand::thread_rng()) as f32;
-            let td = object.torch_distance;
-            fov_map.compute_fov(object.x, object.y, td, FOV_LIGHT_WALLS, FOV_ALGO);
-            for y in (object.y - td)..(object.y + td + 1) {
-                if y < 0 || y >= MAP_HEIGHT {
-                    continue;
+    for object in objects.iter_mut() {
+        if object.viewshed.range <= 0 {
+            continue;
+        }
+
+        if object.viewshed.dirty {
+            // this is computed on a scratch FOV map, rather than the shared `fov_map`,
+            // so it doesn't clobber the player's main FOV computed below
+            let mut light_fov = FovMap::new(map.width, map.height);
+            for y in 0..map.height {
+                for x in 0..map.width {
+                    let tile = map.get(x, y).unwrap();
+                    light_fov.set(x, y, !tile.block_sight(), !tile.blocked());
                 }
-                for x in (object.x - td)..(object.x + td + 1) {
-                    if x < 0 || x >= MAP_WIDTH {
-                        continue;
-                    }
-                    if fov_map.is_in_fov(x, y) {
-                        let d = 1.0 - Map::distance(object.x, object.y, x, y) / (td as f32) +
-                                torch_intensity_shift;
-                        map[x as usize][y as usize].light_intensity += d;
+            }
+            let range = object.viewshed.range;
+            light_fov.compute_fov(object.x, object.y, range, FOV_LIGHT_WALLS, FOV_ALGO);
+
+            let mut visible_tiles = Vec::new();
+            for y in (object.y - range)..(object.y + range + 1) {
+                for x in (object.x - range)..(object.x + range + 1) {
+                    if map.in_bounds(x, y) && light_fov.is_in_fov(x, y) {
+                        visible_tiles.push((x, y));
                     }
                 }
             }
+            object.viewshed.visible_tiles = visible_tiles;
+        }
+
+        let torch_intensity_shift = Normal::new(0.0, 0.05)
+            .ind_sample(&mut rand::thread_rng()) as f32;
+        let range = object.viewshed.range as f32;
+        for &(x, y) in &object.viewshed.visible_tiles {
+            let d = 1.0 - Map::distance(object.x, object.y, x, y) / range + torch_intensity_shift;
+            if let Some(tile) = map.get_mut(x, y) {
+                tile.light_intensity += d;
+            }
         }
     }
 
-    let player = &objects[0];
-    fov_map.compute_fov(player.x, player.y, SIGHT_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+    if player_dirty {
+        let player = &objects[0];
+        fov_map.compute_fov(player.x, player.y, SIGHT_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+    }
+
+    // the dirty flags have now been consulted everywhere they need to be for this frame
+    for object in objects.iter_mut() {
+        object.viewshed.dirty = false;
+    }
 
-    // go through all tiles, and set their background color
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
+    // go through the tiles within the camera's viewport, and set their background color
+    for y in camera.top_y..camera.bottom_y() {
+        for x in camera.left_x..camera.right_x() {
+            let tile = *map.get(x, y).expect("camera viewport should stay within map bounds");
             let visible = fov_map.is_in_fov(x, y);
-            let wall = map[x as usize][y as usize].block_sight;
-            let intensity = map[x as usize][y as usize]
-                .light_intensity
-                .min(1.0)
-                .max(0.0);
+            let wall = tile.block_sight();
+            let intensity = tile.light_intensity.min(1.0).max(0.0);
             let color = match (visible, wall) {
                 // outside of field of view:
                 (false, true) => COLOR_FOG_WALL,
@@ -138,27 +301,41 @@ fn render_all(root: &mut Root,
                 (true, false) => lerp(COLOR_DARK_GROUND, COLOR_LIGHT_GROUND, intensity),
             };
 
-            let explored = &mut map[x as usize][y as usize].explored;
-            if visible && intensity > 0.0 {
-                // since it's visible, explore it
-                *explored = true;
+            // since it's visible, explore it
+            let explored = tile.explored || (visible && intensity > 0.0);
+            if explored && !tile.explored {
+                map.get_mut(x, y).unwrap().explored = true;
             }
-            if *explored {
+            if explored {
                 // show explored tiles only (any visible tile is explored already)
-                con.set_char_background(x, y, color, BackgroundFlag::Set);
+                let (screen_x, screen_y) = camera.to_screen(x, y).unwrap();
+                con.set_char_background(screen_x, screen_y, color, BackgroundFlag::Set);
             }
         }
     }
 
-    // draw all objects in the list
-    for object in objects {
+    // draw all objects in the list that are both in sight and in view
+    for object in objects.iter() {
         if fov_map.is_in_fov(object.x, object.y) {
-            object.draw(con);
+            object.draw(con, camera);
         }
     }
 
     // blit the contents of "con" to the root console
-    blit(con, (0, 0), (MAP_WIDTH, MAP_HEIGHT), root, (0, 0), 1.0, 1.0);
+    blit(con,
+         (0, 0),
+         (DISPLAY_WIDTH, DISPLAY_HEIGHT),
+         root,
+         (0, 0),
+         1.0,
+         1.0);
+}
+
+/// the outcome of a single call to `handle_keys`, used to drive the `RunState` machine
+enum PlayerAction {
+    TookTurn,
+    DidntTakeTurn,
+    Exit,
 }
 
 fn handle_keys(key: Key,
@@ -166,8 +343,9 @@ fn handle_keys(key: Key,
                player_idx: usize,
                objects: &mut Vec<Object>,
                map: &Map)
-               -> bool {
+               -> PlayerAction {
     use tcod::input::KeyCode::*;
+    use PlayerAction::*;
 
     match key {
         Key {
@@ -177,25 +355,38 @@ fn handle_keys(key: Key,
         } => {
             let fullscreen = root.is_fullscreen();
             root.set_fullscreen(!fullscreen);
+            DidntTakeTurn
+        }
+        Key { code: Escape, .. } => Exit,
+        Key { code: Up, .. } => {
+            move_by(player_idx, 0, -1, map, objects);
+            TookTurn
+        }
+        Key { code: Down, .. } => {
+            move_by(player_idx, 0, 1, map, objects);
+            TookTurn
+        }
+        Key { code: Left, .. } => {
+            move_by(player_idx, -1, 0, map, objects);
+            TookTurn
+        }
+        Key { code: Right, .. } => {
+            move_by(player_idx, 1, 0, map, objects);
+            TookTurn
         }
-        Key { code: Escape, .. } => return true,
-        Key { code: Up, .. } => objects[player_idx].move_by(0, -1, map),
-        Key { code: Down, .. } => objects[player_idx].move_by(0, 1, map),
-        Key { code: Left, .. } => objects[player_idx].move_by(-1, 0, map),
-        Key { code: Right, .. } => objects[player_idx].move_by(1, 0, map),
         Key { code: Spacebar, .. } => {
             let (x, y) = {
                 let player = &objects[player_idx];
                 (player.x, player.y)
             };
-            let mut torch = Object::new(x, y, 'i', colors::COPPER);
-            torch.torch_distance = 5;
+            let mut torch = Object::new(x, y, 'i', colors::COPPER, false);
+            torch.set_light_range(5);
             objects.push(torch);
+            TookTurn
         }
 
-        _ => {}
+        _ => DidntTakeTurn,
     }
-    false
 }
 
 fn main() {
@@ -208,31 +399,52 @@ fn main() {
 
     tcod::system::set_fps(LIMIT_FPS);
 
-    let mut con = Offscreen::new(MAP_WIDTH, MAP_HEIGHT);
+    let mut con = Offscreen::new(DISPLAY_WIDTH, DISPLAY_HEIGHT);
 
-    // generate map (at this point it's not drawn to the screen)
-    let (mut map, (player_x, player_y)) = make_map();
+    // pick a dungeon generation algorithm at random and build the map from it (at this
+    // point it's not drawn to the screen)
+    let seed = rand::thread_rng().gen::<u32>();
+    let builder: Box<MapBuilder> = if rand::random() {
+        Box::new(RoomsAndCorridors)
+    } else {
+        Box::new(CaveGenerator)
+    };
+    let (mut map, (player_x, player_y)) = builder.build(seed);
 
     // create object representing the player
     // place the player inside the first room
-    let mut player = Object::new(player_x, player_y, '@', colors::WHITE);
-    player.torch_distance = 5;
+    let mut player = Object::new(player_x, player_y, '@', colors::WHITE, true);
+    player.set_light_range(5);
 
-    // the list of objects with those two
+    // the list of objects, starting with the player
     let mut objects = vec![player];
 
+    // populate the rest of the rooms with monsters that chase the player on sight
+    for room in map.rooms.iter().skip(1) {
+        let (x, y) = room.center();
+        let mut monster = if rand::random() {
+            Object::new(x, y, 'o', colors::DESATURATED_GREEN, true)
+        } else {
+            Object::new(x, y, 'T', colors::DARKER_GREEN, true)
+        };
+        monster.monster = true;
+        objects.push(monster);
+    }
+
     // create the FOV map, according to the generated map
-    let mut fov_map = FovMap::new(MAP_WIDTH, MAP_HEIGHT);
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
-            fov_map.set(x,
-                        y,
-                        !map[x as usize][y as usize].block_sight,
-                        !map[x as usize][y as usize].blocked);
+    let mut fov_map = FovMap::new(map.width, map.height);
+    for y in 0..map.height {
+        for x in 0..map.width {
+            let tile = map.get(x, y).unwrap();
+            fov_map.set(x, y, !tile.block_sight(), !tile.blocked());
         }
     }
 
+    // the camera starts centered on the player
+    let mut camera = Camera::new(player_x, player_y);
+
     let mut key;
+    let mut run_state = RunState::WaitingForInput;
 
     while !root.window_closed() {
         match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
@@ -241,19 +453,39 @@ fn main() {
         }
 
         // render the screen
-        render_all(&mut root, &mut con, &objects, &mut map, &mut fov_map);
+        render_all(&mut root, &mut con, &mut objects, &mut map, &mut fov_map, &camera);
 
         root.flush();
 
         // erase all objects at their old locations, before they move
         for object in &objects {
-            object.clear(&mut con)
+            object.clear(&mut con, &camera)
         }
 
-        // handle keys and exit game if needed
-        let exit = handle_keys(key, &mut root, 0, &mut objects, &map);
-        if exit {
-            break;
-        }
+        // advance exactly one phase of the turn cycle per frame: the game sits in
+        // WaitingForInput (rendering but not simulating) until the player's key causes a
+        // turn, at which point PlayerTurn and MonsterTurn run once each before returning
+        // to waiting
+        run_state = match run_state {
+            RunState::WaitingForInput => {
+                match handle_keys(key, &mut root, 0, &mut objects, &map) {
+                    PlayerAction::Exit => break,
+                    PlayerAction::TookTurn => RunState::PlayerTurn,
+                    PlayerAction::DidntTakeTurn => RunState::WaitingForInput,
+                }
+            }
+            RunState::PlayerTurn => RunState::MonsterTurn,
+            RunState::MonsterTurn => {
+                for id in 0..objects.len() {
+                    if objects[id].monster {
+                        ai_take_turn(id, &map, &mut objects);
+                    }
+                }
+                RunState::WaitingForInput
+            }
+        };
+
+        // recenter the camera in case the player moved this frame
+        camera.center_on(objects[0].x, objects[0].y);
     }
 }
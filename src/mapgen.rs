@@ -1,20 +1,37 @@
 
 use std::cmp;
-use std::ops;
-use rand::{self, Rng};
+use rand::{Rng, SeedableRng, StdRng};
 
-// size of the map
-pub const MAP_WIDTH: i32 = 80;
-pub const MAP_HEIGHT: i32 = 45;
+// size of the map, which may be much larger than what fits on screen at once --
+// see `Camera` in main.rs for how the visible window scrolls over it
+pub const MAP_WIDTH: i32 = 160;
+pub const MAP_HEIGHT: i32 = 90;
 
 //parameters for dungeon generator
 const ROOM_MAX_SIZE: i32 = 10;
 const ROOM_MIN_SIZE: i32 = 6;
-const MAX_ROOMS: i32 = 30;
-
-pub struct Map(Vec<Vec<Tile>>);
+const MAX_ROOMS: i32 = 60;
+
+/// The dungeon: a flat grid of tiles plus the rooms that were carved into it. Storing
+/// tiles flat instead of as a `Vec<Vec<Tile>>` keeps them contiguous in memory, and makes
+/// it possible to offer bounds-checked access instead of panicking on bad coordinates.
+pub struct Map {
+    tiles: Vec<Tile>,
+    pub width: i32,
+    pub height: i32,
+    pub rooms: Vec<Rect>,
+}
 
 impl Map {
+    pub fn new(width: i32, height: i32) -> Self {
+        Map {
+            tiles: vec![Tile::wall(); (width * height) as usize],
+            width: width,
+            height: height,
+            rooms: Vec::new(),
+        }
+    }
+
     pub fn distance(x0: i32, y0: i32, x1: i32, y1: i32) -> f32 {
         let dx = x1 - x0;
         let dy = y1 - y0;
@@ -22,61 +39,87 @@ impl Map {
         (d2 as f32).sqrt()
     }
 
-    pub fn clear_light(&mut self) {
-        for mut row in self.0.iter_mut() {
-            for mut cell in row.iter_mut() {
-                cell.light_intensity = 0.0;
-            }
+    pub fn xy_idx(&self, x: i32, y: i32) -> usize {
+        (y * self.width + x) as usize
+    }
+
+    pub fn in_bounds(&self, x: i32, y: i32) -> bool {
+        x >= 0 && x < self.width && y >= 0 && y < self.height
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> Option<&Tile> {
+        if self.in_bounds(x, y) {
+            Some(&self.tiles[self.xy_idx(x, y)])
+        } else {
+            None
         }
     }
-}
 
-impl ops::Index<usize> for Map {
-    type Output = Vec<Tile>;
+    pub fn get_mut(&mut self, x: i32, y: i32) -> Option<&mut Tile> {
+        if self.in_bounds(x, y) {
+            let idx = self.xy_idx(x, y);
+            Some(&mut self.tiles[idx])
+        } else {
+            None
+        }
+    }
 
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.0[index]
+    pub fn clear_light(&mut self) {
+        for tile in self.tiles.iter_mut() {
+            tile.light_intensity = 0.0;
+        }
     }
-}
 
-impl ops::IndexMut<usize> for Map {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.0[index]
+    fn set(&mut self, x: i32, y: i32, tile: Tile) {
+        let idx = self.xy_idx(x, y);
+        self.tiles[idx] = tile;
     }
 }
 
+/// the kind of terrain a tile represents
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileType {
+    Wall,
+    Floor,
+}
+
 /// A tile of the map and its properties
 #[derive(Clone, Copy, Debug)]
 pub struct Tile {
-    pub blocked: bool,
+    pub tile_type: TileType,
     pub explored: bool,
-    pub block_sight: bool,
     pub light_intensity: f32,
 }
 
 impl Tile {
-    pub fn empty() -> Self {
+    pub fn floor() -> Self {
         Tile {
-            blocked: false,
+            tile_type: TileType::Floor,
             explored: false,
-            block_sight: false,
             light_intensity: 0.0,
         }
     }
 
     pub fn wall() -> Self {
         Tile {
-            blocked: true,
+            tile_type: TileType::Wall,
             explored: false,
-            block_sight: true,
             light_intensity: 0.0,
         }
     }
+
+    pub fn blocked(&self) -> bool {
+        self.tile_type == TileType::Wall
+    }
+
+    pub fn block_sight(&self) -> bool {
+        self.tile_type == TileType::Wall
+    }
 }
 
 /// A rectangle on the map, used to characterise a room.
 #[derive(Clone, Copy, Debug)]
-struct Rect {
+pub struct Rect {
     x1: i32,
     y1: i32,
     x2: i32,
@@ -110,7 +153,7 @@ fn create_room(room: Rect, map: &mut Map) {
     // go through the tiles in the rectangle and make them passable
     for x in (room.x1 + 1)..room.x2 {
         for y in (room.y1 + 1)..room.y2 {
-            map[x as usize][y as usize] = Tile::empty();
+            map.set(x, y, Tile::floor());
         }
     }
 }
@@ -118,75 +161,242 @@ fn create_room(room: Rect, map: &mut Map) {
 fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
     // horizontal tunnel. `min()` and `max()` are used in case `x1 > x2`
     for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
+        map.set(x, y, Tile::floor());
     }
 }
 
 fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
     // vertical tunnel
     for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
+        map.set(x, y, Tile::floor());
     }
 }
 
-pub fn make_map() -> (Map, (i32, i32)) {
-    // fill map with "blocked" tiles
-    let mut map = Map(vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize]);
+/// a dungeon generation algorithm: builds a full map plus the position the player should
+/// start at. Taking an RNG seed rather than reaching for `rand::thread_rng()` makes the
+/// generated dungeon reproducible, so a run can be tested or shared by seed.
+pub trait MapBuilder {
+    fn build(&self, seed: u32) -> (Map, (i32, i32));
+}
 
-    let mut rooms = vec![];
+/// the original generator: carves non-overlapping rectangular rooms and connects them
+/// with L-shaped corridors
+pub struct RoomsAndCorridors;
 
-    let mut starting_position = (0, 0);
+impl MapBuilder for RoomsAndCorridors {
+    fn build(&self, seed: u32) -> (Map, (i32, i32)) {
+        let mut rng = StdRng::from_seed(&[seed as usize][..]);
 
-    for _ in 0..MAX_ROOMS {
-        // random width and height
-        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        // random position without going out of the boundaries of the map
-        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
-        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
+        // fill map with "blocked" tiles
+        let mut map = Map::new(MAP_WIDTH, MAP_HEIGHT);
 
-        let new_room = Rect::new(x, y, w, h);
+        let mut starting_position = (0, 0);
 
-        // run through the other rooms and see if they intersect with this one
-        let failed = rooms
-            .iter()
-            .any(|other_room| new_room.intersects_with(other_room));
+        for _ in 0..MAX_ROOMS {
+            // random width and height
+            let w = rng.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+            let h = rng.gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+            // random position without going out of the boundaries of the map
+            let x = rng.gen_range(0, MAP_WIDTH - w);
+            let y = rng.gen_range(0, MAP_HEIGHT - h);
 
-        if !failed {
-            // this means there are no intersections, so this room is valid
+            let new_room = Rect::new(x, y, w, h);
 
-            // "paint" it to the map's tiles
-            create_room(new_room, &mut map);
+            // run through the other rooms and see if they intersect with this one
+            let failed = map.rooms
+                .iter()
+                .any(|other_room| new_room.intersects_with(other_room));
 
-            // center coordinates of the new room, will be useful later
-            let (new_x, new_y) = new_room.center();
+            if !failed {
+                // this means there are no intersections, so this room is valid
 
-            if rooms.is_empty() {
-                // this is the first room, where the player starts at
-                starting_position = (new_x, new_y);
-            } else {
-                // all rooms after the first:
-                // connect it to the previous room with a tunnel
+                // "paint" it to the map's tiles
+                create_room(new_room, &mut map);
 
-                // center coordinates of the previous room
-                let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
+                // center coordinates of the new room, will be useful later
+                let (new_x, new_y) = new_room.center();
 
-                // toss a coin (random bool value -- either true or false)
-                if rand::random() {
-                    // first move horizontally, then vertically
-                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
-                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
+                if map.rooms.is_empty() {
+                    // this is the first room, where the player starts at
+                    starting_position = (new_x, new_y);
                 } else {
-                    // first move vertically, then horizontally
-                    create_v_tunnel(prev_y, new_y, prev_x, &mut map);
-                    create_h_tunnel(prev_x, new_x, new_y, &mut map);
+                    // all rooms after the first:
+                    // connect it to the previous room with a tunnel
+
+                    // center coordinates of the previous room
+                    let (prev_x, prev_y) = map.rooms[map.rooms.len() - 1].center();
+
+                    // toss a coin (random bool value -- either true or false)
+                    if rng.gen() {
+                        // first move horizontally, then vertically
+                        create_h_tunnel(prev_x, new_x, prev_y, &mut map);
+                        create_v_tunnel(prev_y, new_y, new_x, &mut map);
+                    } else {
+                        // first move vertically, then horizontally
+                        create_v_tunnel(prev_y, new_y, prev_x, &mut map);
+                        create_h_tunnel(prev_x, new_x, new_y, &mut map);
+                    }
                 }
+
+                // finally, append the new room to the list
+                map.rooms.push(new_room);
             }
+        }
 
-            // finally, append the new room to the list
-            rooms.push(new_room);
+        (map, starting_position)
+    }
+}
+
+// parameters for the cave generator
+const CAVE_WALL_SEED_CHANCE: u32 = 45;
+const CAVE_SMOOTHING_PASSES: u32 = 4;
+const CAVE_WALL_NEIGHBOR_THRESHOLD: usize = 5;
+const CAVE_SPAWN_POINTS: usize = 20;
+
+/// counts how many of the 8 neighbors of (x, y) are walls, treating the edge of the map
+/// as solid so caves don't leak open at the border
+fn count_wall_neighbors(map: &Map, x: i32, y: i32) -> usize {
+    let mut count = 0;
+    for dy in -1..2 {
+        for dx in -1..2 {
+            if dx == 0 && dy == 0 {
+                continue;
+            }
+            let is_wall = match map.get(x + dx, y + dy) {
+                Some(tile) => tile.blocked(),
+                None => true,
+            };
+            if is_wall {
+                count += 1;
+            }
         }
     }
+    count
+}
+
+/// one pass of cellular-automata smoothing: a cell becomes a wall if it has at least
+/// `CAVE_WALL_NEIGHBOR_THRESHOLD` wall neighbors, and floor otherwise
+fn smooth_cave(map: &Map) -> Map {
+    let mut next = Map::new(map.width, map.height);
+    for y in 0..map.height {
+        for x in 0..map.width {
+            let tile = if count_wall_neighbors(map, x, y) >= CAVE_WALL_NEIGHBOR_THRESHOLD {
+                Tile::wall()
+            } else {
+                Tile::floor()
+            };
+            next.set(x, y, tile);
+        }
+    }
+    next
+}
 
-    (map, starting_position)
+/// flood-fills the connected region of floor tiles starting at `start`, marking every
+/// tile it visits (wall or floor) in `visited` so the caller can resume scanning after it
+fn flood_fill(map: &Map, start: (i32, i32), visited: &mut [bool]) -> Vec<(i32, i32)> {
+    let mut region = Vec::new();
+    let mut stack = vec![start];
+
+    while let Some((x, y)) = stack.pop() {
+        let idx = map.xy_idx(x, y);
+        if visited[idx] {
+            continue;
+        }
+        visited[idx] = true;
+
+        if map.get(x, y).map_or(true, |tile| tile.blocked()) {
+            continue;
+        }
+        region.push((x, y));
+
+        for &(dx, dy) in &[(0, -1), (0, 1), (-1, 0), (1, 0)] {
+            let (nx, ny) = (x + dx, y + dy);
+            if map.in_bounds(nx, ny) && !visited[map.xy_idx(nx, ny)] {
+                stack.push((nx, ny));
+            }
+        }
+    }
+
+    region
+}
+
+/// finds the largest connected region of floor tiles in the map
+fn find_largest_open_region(map: &Map) -> Vec<(i32, i32)> {
+    let mut visited = vec![false; (map.width * map.height) as usize];
+    let mut largest = Vec::new();
+
+    for y in 0..map.height {
+        for x in 0..map.width {
+            if visited[map.xy_idx(x, y)] {
+                continue;
+            }
+            let region = flood_fill(map, (x, y), &mut visited);
+            if region.len() > largest.len() {
+                largest = region;
+            }
+        }
+    }
+
+    largest
+}
+
+/// walls off every floor tile that isn't part of `region`, so a disconnected pocket can
+/// never be reached by the player
+fn wall_off_everything_but(map: &mut Map, region: &[(i32, i32)]) {
+    let mut keep = vec![false; (map.width * map.height) as usize];
+    for &(x, y) in region {
+        keep[map.xy_idx(x, y)] = true;
+    }
+
+    for y in 0..map.height {
+        for x in 0..map.width {
+            let idx = map.xy_idx(x, y);
+            if !keep[idx] {
+                map.set(x, y, Tile::wall());
+            }
+        }
+    }
+}
+
+/// a natural-looking alternative generator: seeds random noise, smooths it with a few
+/// rounds of cellular automata, then keeps only the largest connected cavern so the
+/// player can always reach every floor tile
+pub struct CaveGenerator;
+
+impl MapBuilder for CaveGenerator {
+    fn build(&self, seed: u32) -> (Map, (i32, i32)) {
+        let mut rng = StdRng::from_seed(&[seed as usize][..]);
+
+        let mut map = Map::new(MAP_WIDTH, MAP_HEIGHT);
+        for y in 0..MAP_HEIGHT {
+            for x in 0..MAP_WIDTH {
+                let tile = if rng.gen_range(0, 100) < CAVE_WALL_SEED_CHANCE {
+                    Tile::wall()
+                } else {
+                    Tile::floor()
+                };
+                map.set(x, y, tile);
+            }
+        }
+
+        for _ in 0..CAVE_SMOOTHING_PASSES {
+            map = smooth_cave(&map);
+        }
+
+        let region = find_largest_open_region(&map);
+        wall_off_everything_but(&mut map, &region);
+
+        // sample a spread of floor tiles from the remaining cavern to stand in for
+        // "rooms", so the player spawn and monster placement work the same as they do
+        // with `RoomsAndCorridors`
+        let mut indices: Vec<usize> = (0..region.len()).collect();
+        rng.shuffle(&mut indices);
+        for &i in indices.iter().take(CAVE_SPAWN_POINTS) {
+            let (x, y) = region[i];
+            map.rooms.push(Rect::new(x, y, 1, 1));
+        }
+
+        let starting_position = map.rooms[0].center();
+        (map, starting_position)
+    }
 }